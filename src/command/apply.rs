@@ -1,10 +1,159 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use clap::{Arg, App, SubCommand, ArgMatches};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
+use serde_json::json;
 
 use crate::nix::{DeploymentTask, DeploymentGoal};
 use crate::nix::host::CopyOptions;
 use crate::deployment::deploy;
 use crate::util;
 
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeStatus {
+    Succeeded,
+    Failed,
+    // Not attempted: an earlier node failed and --keep-going wasn't passed.
+    Cancelled,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeReport {
+    pub name: String,
+    pub goal: String,
+    pub status: NodeStatus,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<SystemTime>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<SystemTime>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u128>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl NodeReport {
+    fn skipped(name: String, goal: DeploymentGoal) -> Self {
+        Self {
+            name,
+            goal: goal.to_string(),
+            status: NodeStatus::Skipped,
+            start: None,
+            end: None,
+            duration_ms: None,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeploymentReport {
+    pub goal: String,
+    pub nodes: Vec<NodeReport>,
+}
+
+impl DeploymentReport {
+    fn succeeded(&self) -> usize {
+        self.nodes.iter().filter(|n| matches!(n.status, NodeStatus::Succeeded)).count()
+    }
+
+    fn failed(&self) -> Vec<&NodeReport> {
+        self.nodes.iter().filter(|n| matches!(n.status, NodeStatus::Failed)).collect()
+    }
+
+    fn cancelled(&self) -> usize {
+        self.nodes.iter().filter(|n| matches!(n.status, NodeStatus::Cancelled)).count()
+    }
+
+    fn skipped(&self) -> usize {
+        self.nodes.iter().filter(|n| matches!(n.status, NodeStatus::Skipped)).count()
+    }
+
+    fn to_summary(&self) -> String {
+        let failed = self.failed();
+
+        let mut summary = format!(
+            "Colmena apply ({}): {} succeeded, {} failed, {} cancelled, {} skipped, {} total",
+            self.goal, self.succeeded(), failed.len(), self.cancelled(), self.skipped(), self.nodes.len(),
+        );
+
+        for node in &failed {
+            let error = node.error.as_deref().unwrap_or("(no error captured)");
+            summary.push_str(&format!("\n  - {}: {}", node.name, error));
+        }
+
+        summary
+    }
+}
+
+// Keeps a hung Matrix homeserver or webhook endpoint from blocking `apply`
+// from returning after the deployment itself has already finished.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn notify_matrix(server: &str, room_id: &str, access_token: &str, report: &DeploymentReport) -> Result<(), reqwest::Error> {
+    // Matrix dedupes on txn_id forever, so it must be unique per call.
+    let nonce = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let txn_id = format!("colmena-{}-{}", report.nodes.len(), nonce);
+    let url = format!(
+        "{}/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
+        server.trim_end_matches('/'), utf8_percent_encode(room_id, NON_ALPHANUMERIC), txn_id,
+    );
+
+    let body = json!({
+        "msgtype": "m.text",
+        "body": report.to_summary(),
+    });
+
+    reqwest::Client::builder()
+        .timeout(NOTIFY_TIMEOUT)
+        .build()?
+        .put(&url)
+        .bearer_auth(access_token)
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+async fn notify_webhook(url: &str, report: &DeploymentReport) -> Result<(), reqwest::Error> {
+    reqwest::Client::builder()
+        .timeout(NOTIFY_TIMEOUT)
+        .build()?
+        .post(url)
+        .json(report)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+fn validate_parallelism(s: &str) -> Result<(), String> {
+    match s {
+        "auto" => Ok(()),
+        _ => match s.parse::<usize>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(String::from("The value must be a valid number or \"auto\"")),
+        },
+    }
+}
+
+fn parse_parallelism(s: &str) -> usize {
+    match s {
+        "auto" => num_cpus::get().max(1),
+        s => s.parse::<usize>().unwrap(),
+    }
+}
+
 pub fn subcommand() -> App<'static, 'static> {
     let command = SubCommand::with_name("apply")
         .about("Apply configurations on remote machines")
@@ -22,15 +171,12 @@ pub fn subcommand() -> App<'static, 'static> {
             .long_help(r#"Limits the maximum number of hosts to be deployed in parallel.
 
 Set to 0 to disable parallemism limit.
+
+Set to "auto" to use the number of logical CPUs on this machine.
 "#)
             .default_value("10")
             .takes_value(true)
-            .validator(|s| {
-                match s.parse::<usize>() {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err(String::from("The value must be a valid number")),
-                }
-            }))
+            .validator(|s| validate_parallelism(&s)))
         .arg(Arg::with_name("verbose")
             .short("v")
             .long("verbose")
@@ -47,6 +193,29 @@ Set to 0 to disable parallemism limit.
             .help("Do not use gzip")
             .long_help("Disables the use of gzip when copying closures to the remote host.")
             .takes_value(false))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Output machine-readable JSON")
+            .long_help("Suppresses the progress spinner and Colmena's own status messages, and prints a single JSON document summarizing the run to stdout once it completes. Output of the deployment steps themselves is not suppressed: without the spinner it is printed line by line, as with --verbose.")
+            .takes_value(false))
+        .arg(Arg::with_name("notify-matrix")
+            .long("notify-matrix")
+            .value_names(&["SERVER", "ROOM_ID", "ACCESS_TOKEN"])
+            .help("Send a Matrix notification after deployment")
+            .long_help("Once the deployment finishes, sends a summary of the run (succeeded/failed/skipped counts and per-failed-node errors) as a message to the given Matrix room.")
+            .number_of_values(3)
+            .takes_value(true))
+        .arg(Arg::with_name("notify-webhook")
+            .long("notify-webhook")
+            .value_name("URL")
+            .help("Send a webhook notification after deployment")
+            .long_help("Once the deployment finishes, POSTs the same JSON document produced by --json to the given URL.")
+            .takes_value(true))
+        .arg(Arg::with_name("keep-going")
+            .long("keep-going")
+            .help("Continue deploying to other nodes after a failure")
+            .long_help("By default, once a node fails to activate, no not-yet-started nodes are scheduled (in-flight ones are allowed to finish). Pass this to ignore failures and attempt every selected node regardless.")
+            .takes_value(false))
     ;
 
     util::register_selector_args(command)
@@ -54,8 +223,11 @@ Set to 0 to disable parallemism limit.
 
 pub async fn run(_global_args: &ArgMatches<'_>, local_args: &ArgMatches<'_>) {
     let mut hive = util::hive_from_args(local_args).unwrap();
+    let json_output = local_args.is_present("json");
 
-    log::info!("Enumerating nodes...");
+    if !json_output {
+        log::info!("Enumerating nodes...");
+    }
     let all_nodes = hive.deployment_info().await.unwrap();
 
     let selected_nodes = match local_args.value_of("on") {
@@ -70,18 +242,21 @@ pub async fn run(_global_args: &ArgMatches<'_>, local_args: &ArgMatches<'_>) {
         quit::with_code(2);
     }
 
-    if selected_nodes.len() == all_nodes.len() {
-        log::info!("Building all node configurations...");
-    } else {
-        log::info!("Selected {} out of {} hosts. Building node configurations...", selected_nodes.len(), all_nodes.len());
+    if !json_output {
+        if selected_nodes.len() == all_nodes.len() {
+            log::info!("Building all node configurations...");
+        } else {
+            log::info!("Selected {} out of {} hosts. Building node configurations...", selected_nodes.len(), all_nodes.len());
+        }
     }
 
     // Some ugly argument mangling :/
     let mut profiles = hive.build_selected(selected_nodes).await.unwrap();
     let goal = DeploymentGoal::from_str(local_args.value_of("goal").unwrap()).unwrap();
     let verbose = local_args.is_present("verbose");
+    let keep_going = local_args.is_present("keep-going");
 
-    let max_parallelism = local_args.value_of("parallel").unwrap().parse::<usize>().unwrap();
+    let max_parallelism = parse_parallelism(local_args.value_of("parallel").unwrap());
     let max_parallelism = match max_parallelism {
         0 => None,
         _ => Some(max_parallelism),
@@ -109,11 +284,138 @@ pub async fn run(_global_args: &ArgMatches<'_>, local_args: &ArgMatches<'_>) {
         }
     }
 
-    if skip_list.len() != 0 {
-        log::info!("Applying configurations ({} skipped)...", skip_list.len());
-    } else {
-        log::info!("Applying configurations...");
+    if !json_output {
+        if skip_list.len() != 0 {
+            log::info!("Applying configurations ({} skipped)...", skip_list.len());
+        } else {
+            log::info!("Applying configurations...");
+        }
+    }
+
+    let show_progress = !verbose && !json_output;
+    let node_results = deploy(task_list, max_parallelism, show_progress, keep_going).await;
+
+    let mut nodes: Vec<NodeReport> = node_results
+        .into_iter()
+        .map(|result| {
+            let duration_ms = match (result.start, result.end) {
+                (Some(start), Some(end)) => Some(end.duration_since(start).unwrap_or(Duration::default()).as_millis()),
+                _ => None,
+            };
+
+            let status = if result.cancelled {
+                NodeStatus::Cancelled
+            } else if result.success {
+                NodeStatus::Succeeded
+            } else {
+                NodeStatus::Failed
+            };
+
+            NodeReport {
+                name: result.name,
+                goal: goal.to_string(),
+                status,
+                start: result.start,
+                end: result.end,
+                duration_ms,
+                error: result.error,
+            }
+        })
+        .collect();
+
+    nodes.extend(skip_list.into_iter().map(|name| NodeReport::skipped(name, goal)));
+
+    let report = DeploymentReport {
+        goal: goal.to_string(),
+        nodes,
+    };
+
+    if json_output {
+        println!("{}", serde_json::to_string(&report).unwrap());
+    }
+
+    if let Some(mut matrix_args) = local_args.values_of("notify-matrix") {
+        let server = matrix_args.next().unwrap();
+        let room_id = matrix_args.next().unwrap();
+        let access_token = matrix_args.next().unwrap();
+
+        if let Err(e) = notify_matrix(server, room_id, access_token, &report).await {
+            log::warn!("Failed to send Matrix notification: {}", e);
+        }
     }
 
-    deploy(task_list, max_parallelism, !verbose).await;
+    if let Some(webhook_url) = local_args.value_of("notify-webhook") {
+        if let Err(e) = notify_webhook(webhook_url, &report).await {
+            log::warn!("Failed to send webhook notification: {}", e);
+        }
+    }
+
+    if !report.failed().is_empty() || report.cancelled() > 0 {
+        quit::with_code(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, status: NodeStatus, error: Option<&str>) -> NodeReport {
+        NodeReport {
+            name: name.to_string(),
+            goal: "switch".to_string(),
+            status,
+            start: None,
+            end: None,
+            duration_ms: None,
+            error: error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn to_summary_counts_and_lists_failures() {
+        let report = DeploymentReport {
+            goal: "switch".to_string(),
+            nodes: vec![
+                node("a", NodeStatus::Succeeded, None),
+                node("b", NodeStatus::Failed, Some("activation failed")),
+                node("c", NodeStatus::Cancelled, None),
+                node("d", NodeStatus::Skipped, None),
+            ],
+        };
+
+        let summary = report.to_summary();
+
+        assert!(summary.contains("1 succeeded, 1 failed, 1 cancelled, 1 skipped, 4 total"));
+        assert!(summary.contains("b: activation failed"));
+    }
+
+    #[test]
+    fn to_summary_defaults_missing_error() {
+        let report = DeploymentReport {
+            goal: "switch".to_string(),
+            nodes: vec![node("a", NodeStatus::Failed, None)],
+        };
+
+        assert!(report.to_summary().contains("a: (no error captured)"));
+    }
+
+    #[test]
+    fn node_status_serializes_snake_case() {
+        assert_eq!(serde_json::to_string(&NodeStatus::Succeeded).unwrap(), "\"succeeded\"");
+        assert_eq!(serde_json::to_string(&NodeStatus::Cancelled).unwrap(), "\"cancelled\"");
+    }
+
+    #[test]
+    fn validate_parallelism_accepts_numbers_and_auto() {
+        assert!(validate_parallelism("10").is_ok());
+        assert!(validate_parallelism("0").is_ok());
+        assert!(validate_parallelism("auto").is_ok());
+        assert!(validate_parallelism("not-a-number").is_err());
+    }
+
+    #[test]
+    fn parse_parallelism_resolves_auto_to_at_least_one() {
+        assert!(parse_parallelism("auto") >= 1);
+        assert_eq!(parse_parallelism("5"), 5);
+    }
 }
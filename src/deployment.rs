@@ -0,0 +1,143 @@
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+/// A single host's deployment work, abstracted behind a trait so the
+/// scheduler in `deploy` can be driven in tests without real SSH hosts.
+/// `crate::nix::DeploymentTask` implements this; that impl lives outside
+/// this checkout.
+#[async_trait]
+pub trait Deployable: Send {
+    fn name(&self) -> &str;
+    async fn execute(&self, progress: bool) -> Result<(), String>;
+}
+
+/// Outcome of deploying to a single node, as returned by `deploy`.
+pub struct NodeResult {
+    pub name: String,
+    pub start: Option<SystemTime>,
+    pub end: Option<SystemTime>,
+    pub success: bool,
+    pub cancelled: bool,
+    pub error: Option<String>,
+}
+
+impl NodeResult {
+    fn cancelled(name: String) -> Self {
+        Self {
+            name,
+            start: None,
+            end: None,
+            success: false,
+            cancelled: true,
+            error: None,
+        }
+    }
+}
+
+/// Runs `task_list`, respecting `max_parallelism` (`None` means unlimited).
+///
+/// Tasks are run in batches of up to `max_parallelism` at a time. Unless
+/// `keep_going` is set, a failure anywhere in a batch stops any later batch
+/// from being scheduled at all; those tasks come back `cancelled` rather
+/// than `failed`, since they were never attempted. Tasks already in flight
+/// in the failing batch are always allowed to finish.
+pub async fn deploy<T>(task_list: Vec<T>, max_parallelism: Option<usize>, progress: bool, keep_going: bool) -> Vec<NodeResult>
+where
+    T: Deployable + Send + 'static,
+{
+    let batch_size = max_parallelism.unwrap_or(usize::MAX).max(1);
+    let mut results = Vec::with_capacity(task_list.len());
+    let mut aborted = false;
+    let mut remaining = task_list.into_iter();
+
+    loop {
+        let batch: Vec<T> = remaining.by_ref().take(batch_size).collect();
+        if batch.is_empty() {
+            break;
+        }
+
+        if aborted {
+            results.extend(batch.into_iter().map(|task| NodeResult::cancelled(task.name().to_string())));
+            continue;
+        }
+
+        let mut handles = Vec::with_capacity(batch.len());
+        for task in batch {
+            handles.push(tokio::spawn(async move {
+                let name = task.name().to_string();
+                let start = SystemTime::now();
+                let outcome = task.execute(progress).await;
+                let end = SystemTime::now();
+
+                match outcome {
+                    Ok(()) => NodeResult { name, start: Some(start), end: Some(end), success: true, cancelled: false, error: None },
+                    Err(error) => NodeResult { name, start: Some(start), end: Some(end), success: false, cancelled: false, error: Some(error) },
+                }
+            }));
+        }
+
+        for handle in handles {
+            let result = handle.await.expect("deployment task panicked");
+
+            if !result.success && !keep_going {
+                aborted = true;
+            }
+
+            results.push(result);
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeTask {
+        name: String,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl Deployable for FakeTask {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        async fn execute(&self, _progress: bool) -> Result<(), String> {
+            if self.fail {
+                Err(format!("{} failed", self.name))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn task(name: &str, fail: bool) -> FakeTask {
+        FakeTask { name: name.to_string(), fail }
+    }
+
+    #[tokio::test]
+    async fn fail_fast_cancels_not_yet_started_nodes() {
+        let tasks = vec![task("a", true), task("b", false), task("c", false)];
+        let results = deploy(tasks, Some(1), false, false).await;
+
+        assert_eq!(results.len(), 3);
+        assert!(!results[0].success && !results[0].cancelled, "failing node should be reported failed, not cancelled");
+        assert!(results[1].cancelled, "node after a failure should be cancelled, not attempted");
+        assert!(results[2].cancelled, "node after a failure should be cancelled, not attempted");
+    }
+
+    #[tokio::test]
+    async fn keep_going_attempts_every_node_regardless_of_earlier_failures() {
+        let tasks = vec![task("a", true), task("b", false), task("c", false)];
+        let results = deploy(tasks, Some(1), false, true).await;
+
+        assert_eq!(results.len(), 3);
+        assert!(!results[0].success && !results[0].cancelled);
+        assert!(results[1].success && !results[1].cancelled, "--keep-going should still attempt later nodes");
+        assert!(results[2].success && !results[2].cancelled, "--keep-going should still attempt later nodes");
+    }
+}